@@ -0,0 +1,41 @@
+use crate::ircd::proto::WriteRateLimit;
+
+/// outbound flood control knobs, see [`WriteRateLimit`]; carried in
+/// [`Config`] and handed to each connection's write task. `main` builds
+/// `Config::default()`: nothing reads these values from a file or the
+/// command line, so the defaults of [`WriteRateLimit`] are what is used
+#[derive(Debug, Clone, Copy)]
+pub struct FloodControl {
+    pub capacity: f64,
+    pub refill_per_sec: f64,
+}
+
+impl Default for FloodControl {
+    fn default() -> Self {
+        WriteRateLimit::default().into()
+    }
+}
+
+impl From<FloodControl> for WriteRateLimit {
+    fn from(flood: FloodControl) -> Self {
+        WriteRateLimit {
+            capacity: flood.capacity,
+            refill_per_sec: flood.refill_per_sec,
+        }
+    }
+}
+
+impl From<WriteRateLimit> for FloodControl {
+    fn from(rate_limit: WriteRateLimit) -> Self {
+        FloodControl {
+            capacity: rate_limit.capacity,
+            refill_per_sec: rate_limit.refill_per_sec,
+        }
+    }
+}
+
+/// top-level matrirc configuration
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub flood_control: FloodControl,
+}
@@ -0,0 +1,77 @@
+pub mod proto;
+
+use anyhow::{anyhow, Result};
+use futures::StreamExt;
+use irc::client::prelude::Message;
+use irc::proto::IrcCodec;
+use log::info;
+use std::sync::{Arc, RwLock};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_util::codec::Framed;
+
+use crate::config::Config;
+use crate::matrirc::Matrirc;
+use proto::{ircd_sync_read, ircd_sync_write};
+
+/// handle used to push messages out to the connected irc client and to
+/// read/update the bridge's current nick; cloned cheaply, `nick` is a
+/// snapshot taken when the handle was obtained (see [`Matrirc::irc`])
+#[derive(Clone)]
+pub struct IrcSender {
+    pub nick: String,
+    tx: mpsc::Sender<Message>,
+    shared_nick: Arc<RwLock<String>>,
+}
+
+impl IrcSender {
+    pub async fn send(&self, message: Message) -> Result<()> {
+        self.tx
+            .send(message)
+            .await
+            .map_err(|_| anyhow!("irc write task is gone"))
+    }
+
+    /// update the bridge's local nick used for the prefix on future
+    /// outgoing messages; takes effect for every handle obtained afterwards
+    pub async fn set_nick(&self, nickname: String) -> Result<()> {
+        *self.shared_nick.write().unwrap() = nickname;
+        Ok(())
+    }
+
+    /// re-read the shared nick, for handles that were cloned a while ago
+    pub(crate) fn refreshed(&self) -> IrcSender {
+        let mut refreshed = self.clone();
+        refreshed.nick = self.shared_nick.read().unwrap().clone();
+        refreshed
+    }
+}
+
+/// build a fresh [`IrcSender`] wrapping `tx`, starting out with the
+/// placeholder nick used before the client sends `NICK`
+pub(crate) fn new_irc_sender(tx: mpsc::Sender<Message>) -> IrcSender {
+    IrcSender {
+        nick: "matrirc".to_string(),
+        tx,
+        shared_nick: Arc::new(RwLock::new("matrirc".to_string())),
+    }
+}
+
+/// accept one irc client connection: wire up the read/write halves and run
+/// both sync loops until the client disconnects
+pub async fn handle_connection(
+    stream: TcpStream,
+    config: &Config,
+    matrirc: Matrirc,
+    irc_sink_rx: mpsc::Receiver<Message>,
+) -> Result<()> {
+    let framed = Framed::new(stream, IrcCodec::new("utf8")?);
+    let (writer, reader) = framed.split();
+    let write_rate_limit = config.flood_control.into();
+    let write_task = tokio::spawn(ircd_sync_write(writer, irc_sink_rx, write_rate_limit));
+    let read_task = tokio::spawn(ircd_sync_read(reader, matrirc));
+    write_task.await??;
+    read_task.await??;
+    info!("Connection closed");
+    Ok(())
+}
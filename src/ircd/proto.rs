@@ -1,12 +1,14 @@
 use anyhow::Result;
 use futures::stream::{SplitSink, SplitStream};
 use futures::{SinkExt, TryStreamExt};
-use irc::client::prelude::{Command, Message, Prefix};
-use irc::proto::IrcCodec;
+use irc::client::prelude::{Command, Message, Prefix, Tag};
+use irc::proto::{CapSubCommand, IrcCodec};
 use log::{info, trace, warn};
 use std::cmp::min;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
+use tokio::time::sleep;
 use tokio_util::codec::Framed;
 
 use crate::{matrirc::Matrirc, matrix::proto::MatrixMessageType};
@@ -29,31 +31,140 @@ pub struct IrcMessage {
     pub target: String,
     /// message content
     pub text: String,
+    /// when the matrix event this message bridges actually happened, if
+    /// known; used to emit the IRCv3 `server-time` tag so backlog/history
+    /// doesn't show up stamped with the current wall-clock time
+    pub time: Option<SystemTime>,
 }
 
-impl From<IrcMessage> for Vec<Message> {
-    fn from(message: IrcMessage) -> Self {
+/// IRC lines are capped at this many bytes, CRLF included
+/// (see e.g. RFC 2812 section 2.3, and the `MAX_MSG_LEN` used by sibling IRC crates)
+const MAX_MSG_LEN: usize = 512;
+
+/// byte length of the prefix as rendered on the wire by [`message_of`]
+/// (`from!user@matrirc`)
+fn prefix_wire_len(from: &str) -> usize {
+    let user_len = min(from.len(), 6);
+    from.len() + 1 + user_len + 1 + "matrirc".len()
+}
+
+/// how many bytes of actual text fit in one `:prefix COMMAND target :text\r\n` line
+fn payload_budget(from: &str, command: &str, target: &str) -> usize {
+    // ":" prefix " " command " " target " :" ... "\r\n"
+    let overhead = 1 + prefix_wire_len(from) + 1 + command.len() + 1 + target.len() + 2 + 2;
+    MAX_MSG_LEN.saturating_sub(overhead)
+}
+
+/// wrap `line` into chunks of at most `budget` bytes, preferring to break on
+/// the last whitespace before the limit and never splitting a UTF-8 scalar
+fn wrap_line(line: &str, budget: usize) -> Vec<String> {
+    let budget = budget.max(1);
+    let mut out = Vec::new();
+    let mut rest = line;
+    while rest.len() > budget {
+        let mut cut = budget;
+        while !rest.is_char_boundary(cut) {
+            cut -= 1;
+        }
+        let break_at = rest[..cut].rfind(char::is_whitespace).unwrap_or(cut);
+        // `break_at` can land on 0 (a tiny budget collapsing `cut` below the
+        // first char boundary, or a leading whitespace): always take at
+        // least one whole char so `rest` strictly shrinks every iteration
+        let break_at = if break_at == 0 {
+            rest.char_indices().nth(1).map(|(i, _)| i).unwrap_or(rest.len())
+        } else {
+            break_at
+        };
+        let (head, tail) = rest.split_at(break_at);
+        out.push(head.to_string());
+        rest = tail.trim_start();
+    }
+    out.push(rest.to_string());
+    out
+}
+
+impl IrcMessage {
+    /// convert to the wire messages to send, tagging them with `server-time`
+    /// when `server_time_cap` says the client negotiated that capability
+    /// (falls back to untagged messages otherwise)
+    pub fn into_messages(self, server_time_cap: bool) -> Vec<Message> {
         let IrcMessage {
             text,
             message_type,
             from,
             target,
-        } = message;
+            time,
+        } = self;
+        let command_name = match message_type {
+            IrcMessageType::Privmsg => "PRIVMSG",
+            IrcMessageType::Notice => "NOTICE",
+        };
+        let tags = match (server_time_cap, time) {
+            (true, Some(time)) => Some(vec![Tag("time".to_string(), Some(format_server_time(time)))]),
+            _ => None,
+        };
+        let budget = payload_budget(&from, command_name, &target);
+        // a bare CR (e.g. left over from a Matrix body using "\r\n") isn't
+        // valid inside an IRC line, so strip it before splitting on '\n'
+        let text = text.replace('\r', "");
         text.split('\n')
-            .map(|line| match message_type {
-                IrcMessageType::Privmsg => privmsg(from.clone(), target.clone(), line),
-                IrcMessageType::Notice => notice(from.clone(), target.clone(), line),
+            .flat_map(|line| wrap_line(line, budget))
+            .map(|line| {
+                let command = match message_type {
+                    IrcMessageType::Privmsg => Command::PRIVMSG(target.clone(), line),
+                    IrcMessageType::Notice => Command::NOTICE(target.clone(), line),
+                };
+                message_of_tagged(from.clone(), command, tags.clone())
             })
             .collect()
     }
 }
 
+/// format a timestamp as the IRCv3 `server-time` tag value: RFC3339 with
+/// millisecond precision, e.g. `2024-01-01T12:00:00.000Z`
+fn format_server_time(time: SystemTime) -> String {
+    let dur = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let millis = dur.subsec_millis();
+    let secs = dur.as_secs();
+    let days = (secs / 86400) as i64;
+    let time_of_day = secs % 86400;
+    let (hour, minute, second) = (time_of_day / 3600, (time_of_day / 60) % 60, time_of_day % 60);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, hour, minute, second, millis
+    )
+}
+
+/// days since the unix epoch -> (year, month, day) in the proleptic
+/// Gregorian calendar; Howard Hinnant's `civil_from_days` algorithm
+fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = (z - era * 146097) as u64;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let y = yoe as i64 + era * 400;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = if m <= 2 { y + 1 } else { y };
+    (y, m, d)
+}
+
 fn message_of<S>(prefix: S, command: Command) -> Message
+where
+    S: Into<String>,
+{
+    message_of_tagged(prefix, command, None)
+}
+
+fn message_of_tagged<S>(prefix: S, command: Command, tags: Option<Vec<Tag>>) -> Message
 where
     S: Into<String>,
 {
     Message {
-        tags: None,
+        tags,
         prefix: {
             let p: String = prefix.into();
             // XXX don't compute user from prefix, but use something like
@@ -109,25 +220,126 @@ where
     message_of_noprefix(Command::ERROR(reason.into()))
 }
 
+/// ctcp reply to target, coming as from, with the verb's payload already
+/// formatted: wraps it in the `\u{001}` delimiters ctcp requires
+pub fn ctcp_reply<S, T, U>(from: S, target: T, reply: U) -> Message
+where
+    S: Into<String>,
+    T: Into<String>,
+    U: Into<String>,
+{
+    notice(from, target, format!("\u{001}{}\u{001}", reply.into()))
+}
+
+/// recognized CTCP queries we answer locally instead of bridging to matrix
+#[derive(Debug, Clone)]
+enum CtcpCommand {
+    Version,
+    Ping(String),
+    Time,
+    ClientInfo,
+    Unknown(String),
+}
+
+impl CtcpCommand {
+    /// parse the payload of a `\u{001}...\u{001}`-wrapped ctcp message
+    fn parse(payload: &str) -> Self {
+        let mut parts = payload.splitn(2, ' ');
+        match parts.next().unwrap_or_default() {
+            "VERSION" => CtcpCommand::Version,
+            "PING" => CtcpCommand::Ping(parts.next().unwrap_or_default().to_string()),
+            "TIME" => CtcpCommand::Time,
+            "CLIENTINFO" => CtcpCommand::ClientInfo,
+            other => CtcpCommand::Unknown(other.to_string()),
+        }
+    }
+
+    /// reply text for this verb, or None if it should be dropped
+    fn reply(&self) -> Option<String> {
+        match self {
+            CtcpCommand::Version => Some(format!("VERSION matrirc {}", env!("CARGO_PKG_VERSION"))),
+            CtcpCommand::Ping(token) => Some(format!("PING {}", token)),
+            CtcpCommand::Time => Some(format!("TIME {}", format_server_time(SystemTime::now()))),
+            CtcpCommand::ClientInfo => Some("CLIENTINFO VERSION PING TIME CLIENTINFO".to_string()),
+            CtcpCommand::Unknown(verb) => {
+                info!("Dropping unknown CTCP {}", verb);
+                None
+            }
+        }
+    }
+}
+
+/// token-bucket config for [`ircd_sync_write`]'s outbound flood control
+///
+/// `capacity` is the max burst size (in messages) and `refill_per_sec` is
+/// how many tokens are earned back per second once the bucket is drained.
+#[derive(Debug, Clone, Copy)]
+pub struct WriteRateLimit {
+    pub capacity: f64,
+    pub refill_per_sec: f64,
+}
+
+impl Default for WriteRateLimit {
+    fn default() -> Self {
+        WriteRateLimit {
+            capacity: 10.0,
+            refill_per_sec: 1.0 / 1.5,
+        }
+    }
+}
+
 pub async fn ircd_sync_write(
     mut writer: SplitSink<Framed<TcpStream, IrcCodec>, Message>,
     mut irc_sink_rx: mpsc::Receiver<Message>,
+    rate_limit: WriteRateLimit,
 ) -> Result<()> {
+    let mut tokens = rate_limit.capacity;
+    let mut last_refill = Instant::now();
     while let Some(message) = irc_sink_rx.recv().await {
         match message.command {
             Command::ERROR(_) => {
+                // quit path bypasses the limiter so disconnects are immediate
                 writer.send(message).await?;
                 writer.close().await?;
                 info!("Stopping write task to quit");
                 return Ok(());
             }
-            _ => writer.send(message).await?,
+            _ => {
+                let elapsed = last_refill.elapsed().as_secs_f64();
+                last_refill = Instant::now();
+                tokens = (tokens + elapsed * rate_limit.refill_per_sec).min(rate_limit.capacity);
+                if tokens < 1.0 {
+                    let wait_secs = (1.0 - tokens) / rate_limit.refill_per_sec;
+                    sleep(Duration::from_secs_f64(wait_secs)).await;
+                    tokens = 1.0;
+                    last_refill = Instant::now();
+                }
+                tokens -= 1.0;
+                writer.send(message).await?
+            }
         }
     }
     info!("Stopping write task to sink closed");
     Ok(())
 }
 
+/// best-effort: let the client know a command failed, logging the failure
+/// and, if even the notice can't be sent, logging that too
+async fn report_error(matrirc: &Matrirc, message: &Message, context: &str, e: anyhow::Error) {
+    warn!("{}: {}", context, e);
+    if let Err(e2) = matrirc
+        .irc()
+        .send(notice(
+            &matrirc.irc().nick,
+            message.response_target().unwrap_or("matrirc"),
+            format!("{}: {}", context, e),
+        ))
+        .await
+    {
+        warn!("Furthermore, reply errored too: {}", e2);
+    }
+}
+
 pub async fn ircd_sync_read(
     mut reader: SplitStream<Framed<TcpStream, IrcCodec>>,
     matrirc: Matrirc,
@@ -137,28 +349,43 @@ pub async fn ircd_sync_read(
         match message.command.clone() {
             Command::PING(server, server2) => matrirc.irc().send(pong(server, server2)).await?,
             Command::PRIVMSG(target, msg) => {
-                let (message_type, msg) = if let Some(emote) = msg.strip_prefix("\u{001}ACTION ") {
-                    (MatrixMessageType::Emote, emote.to_string())
-                } else {
-                    (MatrixMessageType::Text, msg)
-                };
+                if let Some(emote) = msg.strip_prefix("\u{001}ACTION ") {
+                    let msg = emote.to_string();
+                    if let Err(e) = matrirc
+                        .mappings()
+                        .to_matrix(&target, MatrixMessageType::Emote, msg)
+                        .await
+                    {
+                        report_error(&matrirc, &message, "Could not forward", e).await;
+                    }
+                    continue;
+                }
+                if let Some(payload) = msg
+                    .strip_prefix('\u{001}')
+                    .and_then(|s| s.strip_suffix('\u{001}'))
+                {
+                    let ctcp = CtcpCommand::parse(payload);
+                    if let Some(reply) = ctcp.reply() {
+                        if let Err(e) = matrirc
+                            .irc()
+                            .send(ctcp_reply(
+                                &matrirc.irc().nick,
+                                message.response_target().unwrap_or("matrirc"),
+                                reply,
+                            ))
+                            .await
+                        {
+                            warn!("Could not send ctcp reply: {}", e);
+                        }
+                    }
+                    continue;
+                }
                 if let Err(e) = matrirc
                     .mappings()
-                    .to_matrix(&target, message_type, msg)
+                    .to_matrix(&target, MatrixMessageType::Text, msg)
                     .await
                 {
-                    warn!("Could not forward message: {}", e);
-                    if let Err(e2) = matrirc
-                        .irc()
-                        .send(notice(
-                            &matrirc.irc().nick,
-                            message.response_target().unwrap_or("matrirc"),
-                            format!("Could not forward: {}", e),
-                        ))
-                        .await
-                    {
-                        warn!("Furthermore, reply errored too: {}", e2);
-                    }
+                    report_error(&matrirc, &message, "Could not forward", e).await;
                 }
             }
             Command::NOTICE(target, msg) => {
@@ -167,19 +394,125 @@ pub async fn ircd_sync_read(
                     .to_matrix(&target, MatrixMessageType::Notice, msg)
                     .await
                 {
-                    warn!("Could not forward message: {}", e);
-                    if let Err(e2) = matrirc
+                    report_error(&matrirc, &message, "Could not forward", e).await;
+                }
+            }
+            Command::JOIN(chanlist, _, _) => {
+                for channel in chanlist.split(',') {
+                    if let Err(e) = matrirc.mappings().join(channel).await {
+                        report_error(
+                            &matrirc,
+                            &message,
+                            &format!("Could not join {}", channel),
+                            e,
+                        )
+                        .await;
+                    }
+                }
+            }
+            Command::PART(chanlist, _) => {
+                for channel in chanlist.split(',') {
+                    if let Err(e) = matrirc.mappings().part(channel).await {
+                        report_error(
+                            &matrirc,
+                            &message,
+                            &format!("Could not part {}", channel),
+                            e,
+                        )
+                        .await;
+                    }
+                }
+            }
+            Command::TOPIC(channel, Some(topic)) => {
+                if let Err(e) = matrirc.mappings().set_topic(&channel, topic).await {
+                    report_error(
+                        &matrirc,
+                        &message,
+                        &format!("Could not set topic for {}", channel),
+                        e,
+                    )
+                    .await;
+                }
+            }
+            Command::TOPIC(channel, None) => match matrirc.mappings().topic(&channel).await {
+                Ok(topic) => {
+                    if let Err(e) = matrirc
                         .irc()
                         .send(notice(
                             &matrirc.irc().nick,
                             message.response_target().unwrap_or("matrirc"),
-                            format!("Could not forward: {}", e),
+                            format!("Topic for {}: {}", channel, topic),
                         ))
                         .await
                     {
-                        warn!("Furthermore, reply errored too: {}", e2);
+                        warn!("Could not reply with topic: {}", e);
+                    }
+                }
+                Err(e) => {
+                    report_error(
+                        &matrirc,
+                        &message,
+                        &format!("Could not fetch topic for {}", channel),
+                        e,
+                    )
+                    .await;
+                }
+            },
+            Command::AWAY(status) => {
+                if let Err(e) = matrirc.matrix().set_presence(status).await {
+                    report_error(&matrirc, &message, "Could not set away status", e).await;
+                }
+            }
+            Command::CAP(_, ref subcommand, _, ref param) => match subcommand {
+                CapSubCommand::LS => {
+                    if let Err(e) = matrirc
+                        .irc()
+                        .send(message_of_noprefix(Command::CAP(
+                            None,
+                            CapSubCommand::LS,
+                            None,
+                            Some("server-time".to_string()),
+                        )))
+                        .await
+                    {
+                        warn!("Could not reply to CAP LS: {}", e);
                     }
                 }
+                CapSubCommand::REQ => {
+                    let requested = param.clone().unwrap_or_default();
+                    let caps: Vec<&str> = requested.split_whitespace().collect();
+                    // CAP REQ is atomic: either every requested cap is
+                    // supported and we ACK the whole list, or we NAK the
+                    // whole list and negotiate nothing from this request
+                    let (reply_subcommand, reply_caps) =
+                        if caps.iter().all(|cap| *cap == "server-time") {
+                            for cap in &caps {
+                                matrirc.ack_cap(cap);
+                            }
+                            (CapSubCommand::ACK, caps)
+                        } else {
+                            (CapSubCommand::NAK, caps)
+                        };
+                    if let Err(e) = matrirc
+                        .irc()
+                        .send(message_of_noprefix(Command::CAP(
+                            None,
+                            reply_subcommand,
+                            None,
+                            Some(reply_caps.join(" ")),
+                        )))
+                        .await
+                    {
+                        warn!("Could not reply to CAP REQ: {}", e);
+                    }
+                }
+                CapSubCommand::END => trace!("CAP negotiation ended"),
+                other => info!("Unhandled CAP subcommand {:?}", other),
+            },
+            Command::NICK(nickname) => {
+                if let Err(e) = matrirc.irc().set_nick(nickname).await {
+                    report_error(&matrirc, &message, "Could not change nick", e).await;
+                }
             }
             _ => info!("Unhandled message {}", message),
         }
@@ -0,0 +1,37 @@
+mod config;
+mod ircd;
+mod mappings;
+mod matrirc;
+mod matrix;
+
+use anyhow::Result;
+use log::{info, warn};
+use matrix_sdk::Client as MatrixClient;
+use tokio::net::TcpListener;
+
+use config::Config;
+use mappings::Mappings;
+use matrirc::Matrirc;
+use matrix::MatrixSender;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    env_logger::init();
+    let config = Config::default();
+    let listener = TcpListener::bind("127.0.0.1:6667").await?;
+    info!("Listening on {}", listener.local_addr()?);
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        info!("New connection from {}", addr);
+        let config = config.clone();
+        let matrix_client = MatrixClient::builder().build().await?;
+        let matrix = MatrixSender::new(matrix_client);
+        let mappings = Mappings::new(matrix.clone());
+        let (matrirc, irc_sink_rx) = Matrirc::new(mappings, matrix);
+        tokio::spawn(async move {
+            if let Err(e) = ircd::handle_connection(stream, &config, matrirc, irc_sink_rx).await {
+                warn!("Connection from {} ended with error: {}", addr, e);
+            }
+        });
+    }
+}
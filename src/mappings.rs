@@ -0,0 +1,74 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use crate::matrix::proto::MatrixMessageType;
+use crate::matrix::MatrixSender;
+
+/// bridges an IRC channel/query name to the matrix room it's mapped to;
+/// lookup goes one way only (irc name -> room id), there is no reverse
+/// table; one `Mappings` is shared per connected client
+pub struct Mappings {
+    matrix: MatrixSender,
+    by_irc_name: RwLock<HashMap<String, String>>,
+}
+
+impl Mappings {
+    pub fn new(matrix: MatrixSender) -> Self {
+        Mappings {
+            matrix,
+            by_irc_name: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn room_id(&self, irc_name: &str) -> Result<String> {
+        self.by_irc_name
+            .read()
+            .unwrap()
+            .get(irc_name)
+            .cloned()
+            .with_context(|| format!("no room mapped to {}", irc_name))
+    }
+
+    /// forward an outgoing irc message to the matrix room mapped to `target`
+    pub async fn to_matrix(
+        &self,
+        target: &str,
+        message_type: MatrixMessageType,
+        text: String,
+    ) -> Result<()> {
+        let room_id = self.room_id(target)?;
+        self.matrix.send_message(&room_id, message_type, text).await
+    }
+
+    /// join the matrix room mapped to `channel`, creating the mapping if
+    /// this is the first time we see it
+    pub async fn join(&self, channel: &str) -> Result<()> {
+        let room_id = self.matrix.join_room(channel).await?;
+        self.by_irc_name
+            .write()
+            .unwrap()
+            .insert(channel.to_string(), room_id.to_string());
+        Ok(())
+    }
+
+    /// leave the matrix room mapped to `channel`
+    pub async fn part(&self, channel: &str) -> Result<()> {
+        let room_id = self.room_id(channel)?;
+        self.matrix.leave_room(&room_id).await?;
+        self.by_irc_name.write().unwrap().remove(channel);
+        Ok(())
+    }
+
+    /// set the matrix room topic mapped to `channel`
+    pub async fn set_topic(&self, channel: &str, topic: String) -> Result<()> {
+        let room_id = self.room_id(channel)?;
+        self.matrix.set_topic(&room_id, topic).await
+    }
+
+    /// fetch the current matrix room topic mapped to `channel`
+    pub async fn topic(&self, channel: &str) -> Result<String> {
+        let room_id = self.room_id(channel)?;
+        self.matrix.topic(&room_id).await
+    }
+}
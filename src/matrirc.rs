@@ -0,0 +1,66 @@
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+
+use irc::client::prelude::Message;
+use tokio::sync::mpsc;
+
+use crate::ircd::{new_irc_sender, IrcSender};
+use crate::mappings::Mappings;
+use crate::matrix::MatrixSender;
+
+struct Inner {
+    irc: IrcSender,
+    mappings: Mappings,
+    matrix: MatrixSender,
+    /// CAPs the connected client negotiated via `CAP REQ`/`CAP ACK`
+    caps: RwLock<HashSet<String>>,
+}
+
+/// shared handle threaded through the irc<->matrix bridging code for one
+/// connected client; cheap to clone (it's an `Arc` underneath)
+#[derive(Clone)]
+pub struct Matrirc {
+    inner: Arc<Inner>,
+}
+
+impl Matrirc {
+    /// build a new bridge handle for one client connection; returns the
+    /// receiver end [`crate::ircd::proto::ircd_sync_write`] should drain
+    pub fn new(mappings: Mappings, matrix: MatrixSender) -> (Matrirc, mpsc::Receiver<Message>) {
+        let (tx, rx) = mpsc::channel(64);
+        let irc = new_irc_sender(tx);
+        let matrirc = Matrirc {
+            inner: Arc::new(Inner {
+                irc,
+                mappings,
+                matrix,
+                caps: RwLock::new(HashSet::new()),
+            }),
+        };
+        (matrirc, rx)
+    }
+
+    /// snapshot of the current irc sender (including its current nick);
+    /// cheap to call repeatedly, always reflects the latest negotiated nick
+    pub fn irc(&self) -> IrcSender {
+        self.inner.irc.refreshed()
+    }
+
+    pub fn mappings(&self) -> &Mappings {
+        &self.inner.mappings
+    }
+
+    pub fn matrix(&self) -> &MatrixSender {
+        &self.inner.matrix
+    }
+
+    /// record that the client negotiated `cap` during CAP LS/REQ
+    pub fn ack_cap(&self, cap: &str) {
+        self.inner.caps.write().unwrap().insert(cap.to_string());
+    }
+
+    /// whether the client negotiated `cap` (e.g. `"server-time"`)
+    pub fn has_cap(&self, cap: &str) -> bool {
+        self.inner.caps.read().unwrap().contains(cap)
+    }
+}
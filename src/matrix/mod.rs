@@ -0,0 +1,85 @@
+pub mod proto;
+pub mod sync;
+
+use anyhow::{Context, Result};
+use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+use matrix_sdk::ruma::presence::PresenceState;
+use matrix_sdk::ruma::{OwnedRoomId, RoomId};
+use matrix_sdk::Client;
+
+use proto::MatrixMessageType;
+
+/// thin wrapper around the matrix-sdk client for the handful of actions
+/// the irc side needs to trigger; cloned cheaply (matrix-sdk's `Client`
+/// is itself an `Arc` handle)
+#[derive(Clone)]
+pub struct MatrixSender {
+    client: Client,
+}
+
+impl MatrixSender {
+    pub fn new(client: Client) -> Self {
+        MatrixSender { client }
+    }
+
+    fn room(&self, room_id: &str) -> Result<matrix_sdk::room::Joined> {
+        let room_id = RoomId::parse(room_id).context("invalid room id")?;
+        self.client
+            .get_joined_room(&room_id)
+            .with_context(|| format!("not joined to {}", room_id))
+    }
+
+    pub async fn send_message(
+        &self,
+        room_id: &str,
+        message_type: MatrixMessageType,
+        text: String,
+    ) -> Result<()> {
+        let room = self.room(room_id)?;
+        let content = match message_type {
+            MatrixMessageType::Text => RoomMessageEventContent::text_plain(text),
+            MatrixMessageType::Emote => RoomMessageEventContent::emote_plain(text),
+            MatrixMessageType::Notice => RoomMessageEventContent::notice_plain(text),
+        };
+        room.send(content, None).await?;
+        Ok(())
+    }
+
+    /// resolve an irc channel name (`#room:server`) to a room id, joining
+    /// it if we're not in it yet, and return the room id to cache in the
+    /// mapping table
+    pub async fn join_room(&self, channel: &str) -> Result<OwnedRoomId> {
+        let joined = self.client.join_room_by_id_or_alias(channel, &[]).await?;
+        Ok(joined.room_id().to_owned())
+    }
+
+    pub async fn leave_room(&self, room_id: &str) -> Result<()> {
+        self.room(room_id)?.leave().await?;
+        Ok(())
+    }
+
+    pub async fn set_topic(&self, room_id: &str, topic: String) -> Result<()> {
+        self.room(room_id)?.set_room_topic(&topic).await?;
+        Ok(())
+    }
+
+    pub async fn topic(&self, room_id: &str) -> Result<String> {
+        self.room(room_id)?
+            .topic()
+            .context("room has no topic set")
+    }
+
+    /// set the bridge account's matrix presence/status message in response
+    /// to an irc `AWAY` command; `None` clears away status
+    pub async fn set_presence(&self, status: Option<String>) -> Result<()> {
+        let (presence, status_msg) = match status {
+            Some(msg) => (PresenceState::Unavailable, Some(msg)),
+            None => (PresenceState::Online, None),
+        };
+        self.client
+            .account()
+            .set_presence(presence, status_msg.as_deref())
+            .await?;
+        Ok(())
+    }
+}
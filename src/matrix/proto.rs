@@ -0,0 +1,46 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use matrix_sdk::ruma::events::room::message::MessageType;
+use matrix_sdk::ruma::MilliSecondsSinceUnixEpoch;
+
+use crate::ircd::proto::{IrcMessage, IrcMessageType};
+
+/// matrix-side classification of an outgoing message, mirrors the matrix
+/// message types matrirc cares about bridging
+#[derive(Debug, Clone, Copy)]
+pub enum MatrixMessageType {
+    Text,
+    Emote,
+    Notice,
+}
+
+/// convert a matrix-sdk message event into the [`IrcMessage`] to relay,
+/// threading the event's origin timestamp through so replayed backlog
+/// doesn't show up stamped with the current wall-clock time
+pub fn irc_message_of(
+    from: String,
+    target: String,
+    message_type: &MessageType,
+    origin_ts: MilliSecondsSinceUnixEpoch,
+) -> IrcMessage {
+    let (message_type, text) = match message_type {
+        MessageType::Emote(content) => (
+            IrcMessageType::Privmsg,
+            format!("\u{001}ACTION {}\u{001}", content.body),
+        ),
+        MessageType::Notice(content) => (IrcMessageType::Notice, content.body.clone()),
+        other => (IrcMessageType::Privmsg, other.body().to_string()),
+    };
+    IrcMessage {
+        message_type,
+        from,
+        target,
+        text,
+        time: system_time_of(origin_ts),
+    }
+}
+
+fn system_time_of(origin_ts: MilliSecondsSinceUnixEpoch) -> Option<SystemTime> {
+    let millis: u64 = origin_ts.0.into();
+    Some(UNIX_EPOCH + Duration::from_millis(millis))
+}
@@ -0,0 +1,14 @@
+use anyhow::Result;
+
+use crate::ircd::proto::IrcMessage;
+use crate::matrirc::Matrirc;
+
+/// push a message bridged from matrix out to the irc client, tagging it
+/// with `server-time` only if the client negotiated that capability
+pub async fn relay_to_irc(matrirc: &Matrirc, message: IrcMessage) -> Result<()> {
+    let server_time_cap = matrirc.has_cap("server-time");
+    for wire_message in message.into_messages(server_time_cap) {
+        matrirc.irc().send(wire_message).await?;
+    }
+    Ok(())
+}